@@ -0,0 +1,57 @@
+#[cfg(feature="bigdecimal")]
+mod bigdecimal {
+    extern crate bigdecimal;
+
+    use self::bigdecimal::BigDecimal;
+    use std::error::Error;
+    use std::io::prelude::*;
+    use std::str;
+    use std::str::FromStr;
+
+    use mysql::Mysql;
+    use types::{self, FromSql, ToSql, ToSqlOutput, IsNull};
+
+    impl ToSql<types::Numeric, Mysql> for BigDecimal {
+        fn to_sql<W: Write>(&self, out: &mut ToSqlOutput<W, Mysql>) -> Result<IsNull, Box<Error + Send + Sync>> {
+            write!(out, "{}", self)?;
+            Ok(IsNull::No)
+        }
+    }
+
+    impl FromSql<types::Numeric, Mysql> for BigDecimal {
+        fn from_sql(bytes: Option<&[u8]>) -> Result<Self, Box<Error + Send + Sync>> {
+            let bytes = match bytes {
+                Some(bytes) => bytes,
+                None => return Err(Box::from("Unexpected NULL for non-null column")),
+            };
+            let string = str::from_utf8(bytes)?;
+            BigDecimal::from_str(string).map_err(|e| Box::new(e) as Box<Error + Send + Sync>)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn bigdecimal_from_sql_parses_the_ascii_decimal_string() {
+            let value = FromSql::<types::Numeric, Mysql>::from_sql(Some(b"1.10"));
+            assert_eq!(BigDecimal::from_str("1.10").unwrap(), value.unwrap());
+
+            let value = FromSql::<types::Numeric, Mysql>::from_sql(Some(b"-123.456"));
+            assert_eq!(BigDecimal::from_str("-123.456").unwrap(), value.unwrap());
+        }
+
+        #[test]
+        fn bigdecimal_from_sql_rejects_malformed_input() {
+            let value: Result<BigDecimal, _> = FromSql::<types::Numeric, Mysql>::from_sql(Some(b"not a number"));
+            assert!(value.is_err());
+        }
+
+        #[test]
+        fn bigdecimal_from_sql_rejects_null() {
+            let value: Result<BigDecimal, _> = FromSql::<types::Numeric, Mysql>::from_sql(None);
+            assert!(value.is_err());
+        }
+    }
+}
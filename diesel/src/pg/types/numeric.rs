@@ -1,14 +1,59 @@
+use std::iter;
+
+/// Packs `digits_10`, the base-10 digits of a nonnegative integer with
+/// `scale` digits after the decimal point, into the base-10000 `digits` and
+/// `weight` that `PgNumeric` stores on the wire, in a single linear pass.
+/// Shared by the `bigdecimal` and `rust_decimal` encoders below, which only
+/// differ in how they extract `digits_10` from their respective mantissa
+/// types.
+#[cfg(any(feature = "bigdecimal", feature = "rust_decimal"))]
+fn group_base_10_digits(digits_10: Vec<u8>, scale: u16) -> (Vec<i16>, i16) {
+    let point = digits_10.len() as i64 - scale as i64;
+    let integer_len = if point > 0 { point } else { 0 };
+
+    // Left-pad the integer part, and right-pad the fractional part, so
+    // both sides of the decimal point land on a 4-digit boundary.
+    let left_pad = ((4 - integer_len.rem_euclid(4)) % 4) as usize;
+    let right_pad = ((4 - (scale as i64).rem_euclid(4)) % 4) as usize;
+    let middle_zeroes = if point < 0 { (-point) as usize } else { 0 };
+
+    let mut padded = Vec::with_capacity(left_pad + middle_zeroes + digits_10.len() + right_pad);
+    padded.extend(iter::repeat(0u8).take(left_pad));
+    padded.extend(iter::repeat(0u8).take(middle_zeroes));
+    padded.extend_from_slice(&digits_10);
+    padded.extend(iter::repeat(0u8).take(right_pad));
+
+    let mut digits = padded
+        .chunks(4)
+        .map(|group| {
+            group.iter().fold(0i16, |acc, &digit| acc * 10 + digit as i16)
+        })
+        .collect::<Vec<_>>();
+
+    let integer_groups = (integer_len + left_pad as i64) / 4;
+    let weight = integer_groups as i16 - 1;
+    let index_of_decimal = integer_groups as usize;
+
+    let unneccessary_zeroes = digits[index_of_decimal..]
+        .iter()
+        .rev()
+        .take_while(|i| **i == 0)
+        .count();
+    let relevant_digits = digits.len() - unneccessary_zeroes;
+    digits.truncate(relevant_digits);
+
+    (digits, weight)
+}
+
 #[cfg(feature="bigdecimal")]
 mod bigdecimal {
     extern crate num_traits;
     extern crate num_bigint;
-    extern crate num_integer;
     extern crate bigdecimal;
 
     use self::bigdecimal::BigDecimal;
     use self::num_bigint::{Sign, BigInt, BigUint};
-    use self::num_integer::Integer;
-    use self::num_traits::{Signed, Zero, ToPrimitive};
+    use self::num_traits::{Signed, Pow};
     use std::error::Error;
     use std::io::prelude::*;
 
@@ -16,57 +61,23 @@ mod bigdecimal {
     use pg::data_types::PgNumeric;
     use types::{self, FromSql, ToSql, ToSqlOutput, IsNull};
 
-    /// Iterator over the digits of a big uint in base 10k.
-    /// The digits will be returned in little endian order.
-    struct ToBase10000(Option<BigUint>);
-
-    impl Iterator for ToBase10000 {
-        type Item = i16;
-
-        fn next(&mut self) -> Option<Self::Item> {
-            self.0.take().map(|v| {
-                let (div, rem) = v.div_rem(&BigUint::from(10000u16));
-                if !div.is_zero() {
-                    self.0 = Some(div);
-                }
-                rem.to_i16().expect("10000 always fits in an i16")
-            })
-        }
+    /// Converts the base-10 digits of `integer` (assumed to have `scale`
+    /// digits after the decimal point) into the base-10000 `digits` and
+    /// `weight` that `PgNumeric` stores on the wire, in a single linear pass.
+    fn to_base_10000(integer: &BigUint, scale: u16) -> (Vec<i16>, i16) {
+        super::group_base_10_digits(integer.to_radix_be(10), scale)
     }
 
     impl<'a> From<&'a BigDecimal> for PgNumeric {
         fn from(decimal: &'a BigDecimal) -> Self {
-            let (mut integer, scale) = decimal.as_bigint_and_exponent();
+            let (integer, scale) = decimal.as_bigint_and_exponent();
             let scale = scale as u16;
-            integer = integer.abs();
-
-            // Ensure that the decimal will always lie on a digit boundary
-            for _ in 0..(4 - scale % 4) {
-                integer = integer * 10;
-            }
-            let integer = integer.to_biguint().expect("integer is always positive");
-
-            let mut digits = ToBase10000(Some(integer)).collect::<Vec<_>>();
-            digits.reverse();
-            let digits_after_decimal = scale as u16 / 4 + 1;
-            let weight = digits.len() as i16 - digits_after_decimal as i16 - 1;
-            let index_of_decimal = (weight + 1) as usize;
-
-            let unneccessary_zeroes = digits[index_of_decimal..]
-                .iter()
-                .rev()
-                .take_while(|i| i.is_zero())
-                .count();
-            let relevant_digits = digits.len() - unneccessary_zeroes;
-            digits.truncate(relevant_digits);
+            let integer = integer.abs().to_biguint().expect("integer is always positive");
+            let (digits, weight) = to_base_10000(&integer, scale);
 
             match decimal.sign() {
-                Sign::Plus => PgNumeric::Positive {
-                    digits, scale, weight
-                },
-                Sign::Minus => PgNumeric::Negative {
-                    digits, scale, weight
-                },
+                Sign::Plus => PgNumeric::Positive { digits, scale, weight },
+                Sign::Minus => PgNumeric::Negative { digits, scale, weight },
                 Sign::NoSign => PgNumeric::Positive {
                     digits: vec![0],
                     scale: 0,
@@ -91,26 +102,39 @@ mod bigdecimal {
 
     impl FromSql<types::Numeric, Pg> for BigDecimal {
         fn from_sql(numeric: Option<&[u8]>) -> Result<Self, Box<Error+Send+Sync>> {
-            let (sign, weight, _, digits) = match PgNumeric::from_sql(numeric)? {
-                PgNumeric::Positive { weight, scale, digits } => (Sign::Plus, weight, scale, digits),
-                PgNumeric::Negative { weight, scale, digits } => (Sign::Minus, weight, scale, digits),
-                PgNumeric::NaN => return Err(Box::from("NaN is not (yet) supported in BigDecimal")),
-            };
-            let mut result = BigUint::default();
-            let count = digits.len() as i64;
-            for digit in digits {
-                result = result * BigUint::from(10_000u64);
-                result = result + BigUint::from(digit as u64);
-            }
-            // First digit got factor 10_000^(digits.len() - 1), but should get 10_000^weight
-            let correction_exp = 4 * ( (weight as i64) - count + 1);
-            // FIXME: `scale` allows to drop some insignificant figures, which is currently unimplemented.
-            // This means that e.g. PostgreSQL 0.01 will be interpreted as 0.0100
-            let result = BigDecimal::new(BigInt::from_biguint(sign, result), -correction_exp);
-            Ok(result)
+            pg_numeric_to_bigdecimal(PgNumeric::from_sql(numeric)?)
         }
     }
 
+    fn pg_numeric_to_bigdecimal(numeric: PgNumeric) -> Result<BigDecimal, Box<Error+Send+Sync>> {
+        let (sign, weight, scale, digits) = match numeric {
+            PgNumeric::Positive { weight, scale, digits } => (Sign::Plus, weight, scale, digits),
+            PgNumeric::Negative { weight, scale, digits } => (Sign::Minus, weight, scale, digits),
+            PgNumeric::NaN => return Err(Box::from("NaN is not (yet) supported in BigDecimal")),
+        };
+        let mut result = BigUint::default();
+        let count = digits.len() as i64;
+        for digit in digits {
+            result = result * BigUint::from(10_000u64);
+            result = result + BigUint::from(digit as u64);
+        }
+        // First digit got factor 10_000^(digits.len() - 1), but should get 10_000^weight
+        let correction_exp = 4 * ( (weight as i64) - count + 1);
+        // `correction_exp` is always a multiple of 4, but `scale` counts exact
+        // base-10 fractional digits, so shift the mantissa the rest of the way
+        // to make the two line up.
+        let fractional_digits_present = -correction_exp;
+        let delta = scale as i64 - fractional_digits_present;
+        if delta > 0 {
+            result = result * BigUint::from(10u64).pow(delta as u32);
+        } else if delta < 0 {
+            // Guaranteed to divide evenly: Pg only ever emits whole base-10000
+            // groups, so the digits being dropped here are all zero.
+            result = result / BigUint::from(10u64).pow((-delta) as u32);
+        }
+        Ok(BigDecimal::new(BigInt::from_biguint(sign, result), scale as i64))
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -188,5 +212,225 @@ mod bigdecimal {
             };
             assert_eq!(expected, decimal.into());
         }
+
+        #[test]
+        fn pg_numeric_to_bigdecimal_properly_respects_scale() {
+            let expected = BigDecimal::from_str("0.01").unwrap();
+            let input = PgNumeric::Positive { weight: -1, scale: 2, digits: vec![100] };
+            assert_eq!(expected, pg_numeric_to_bigdecimal(input).unwrap());
+
+            let expected = BigDecimal::from_str("0.012").unwrap();
+            let input = PgNumeric::Positive { weight: -1, scale: 3, digits: vec![120] };
+            assert_eq!(expected, pg_numeric_to_bigdecimal(input).unwrap());
+
+            let expected = BigDecimal::from_str("1.10").unwrap();
+            let input = PgNumeric::Positive { weight: 0, scale: 2, digits: vec![1, 1000] };
+            assert_eq!(expected, pg_numeric_to_bigdecimal(input).unwrap());
+
+            let expected = BigDecimal::from_str("3").unwrap();
+            let input = PgNumeric::Positive { weight: 0, scale: 0, digits: vec![3] };
+            assert_eq!(expected, pg_numeric_to_bigdecimal(input).unwrap());
+
+            let expected = BigDecimal::from_str("0.00").unwrap();
+            let input = PgNumeric::Positive { weight: 0, scale: 2, digits: vec![0] };
+            assert_eq!(expected, pg_numeric_to_bigdecimal(input).unwrap());
+        }
+
+        #[test]
+        fn bigdecimal_to_pg_numeric_handles_large_values() {
+            // 200 nines followed by a fractional part; exercises the linear
+            // base-10 radix pass on a value far too large for the old
+            // quadratic `div_rem`-based conversion to be practical.
+            let integer_part = "9".repeat(200);
+            let decimal = BigDecimal::from_str(&format!("{}.1234", integer_part)).unwrap();
+
+            let numeric: PgNumeric = (&decimal).into();
+            let roundtripped = pg_numeric_to_bigdecimal(numeric).unwrap();
+            assert_eq!(decimal, roundtripped);
+        }
+    }
+}
+
+#[cfg(feature="rust_decimal")]
+mod rust_decimal {
+    extern crate rust_decimal;
+
+    use std::error::Error;
+    use std::io::prelude::*;
+
+    use self::rust_decimal::Decimal;
+
+    use pg::Pg;
+    use pg::data_types::PgNumeric;
+    use types::{self, FromSql, ToSql, ToSqlOutput, IsNull};
+
+    /// Converts `mantissa` (with `scale` digits after the decimal point) into
+    /// the base-10000 `digits` and `weight` that `PgNumeric` stores on the
+    /// wire, via the same position-based grouping the `bigdecimal` encoder
+    /// uses, rather than relying on a fixed pad count, so it doesn't go wrong
+    /// when `scale` is a multiple of 4 (e.g. every whole-number `Decimal`).
+    fn to_base_10000(mantissa: u128, scale: u16) -> (Vec<i16>, i16) {
+        let mut digits_10 = Vec::new();
+        let mut value = mantissa;
+        if value == 0 {
+            digits_10.push(0);
+        }
+        while value > 0 {
+            digits_10.push((value % 10) as u8);
+            value /= 10;
+        }
+        digits_10.reverse();
+
+        super::group_base_10_digits(digits_10, scale)
+    }
+
+    impl<'a> From<&'a Decimal> for PgNumeric {
+        fn from(decimal: &'a Decimal) -> Self {
+            let scale = decimal.scale() as u16;
+            let mantissa = decimal.mantissa().abs() as u128;
+            let (digits, weight) = to_base_10000(mantissa, scale);
+
+            if decimal.is_sign_negative() {
+                PgNumeric::Negative { digits, scale, weight }
+            } else {
+                PgNumeric::Positive { digits, scale, weight }
+            }
+        }
+    }
+
+    impl ToSql<types::Numeric, Pg> for Decimal {
+        fn to_sql<W: Write>(&self, out: &mut ToSqlOutput<W, Pg>) -> Result<IsNull, Box<Error + Send + Sync>> {
+            let numeric = PgNumeric::from(self);
+            ToSql::<types::Numeric, Pg>::to_sql(&numeric, out)
+        }
+    }
+
+    impl FromSql<types::Numeric, Pg> for Decimal {
+        fn from_sql(numeric: Option<&[u8]>) -> Result<Self, Box<Error + Send + Sync>> {
+            pg_numeric_to_decimal(PgNumeric::from_sql(numeric)?)
+        }
+    }
+
+    fn pg_numeric_to_decimal(numeric: PgNumeric) -> Result<Decimal, Box<Error + Send + Sync>> {
+        let (negative, weight, scale, digits) = match numeric {
+            PgNumeric::Positive { weight, scale, digits } => (false, weight, scale, digits),
+            PgNumeric::Negative { weight, scale, digits } => (true, weight, scale, digits),
+            PgNumeric::NaN => return Err(Box::from("NaN is not supported by rust_decimal::Decimal")),
+        };
+
+        if scale as u32 > Decimal::MAX_SCALE {
+            return Err(Box::from("Numeric value out of range for rust_decimal::Decimal"));
+        }
+
+        // `weight + 1` base-10000 groups make up the integer part; the rest
+        // (`digits.len() + (-weight) - 1`) are fractional groups.
+        let integer_groups = (weight as i64) + 1;
+
+        let mut mantissa: i128 = 0;
+        for digit in &digits {
+            mantissa = mantissa
+                .checked_mul(10_000)
+                .and_then(|m| m.checked_add(*digit as i128))
+                .ok_or_else(|| "Numeric value out of range for rust_decimal::Decimal")?;
+        }
+
+        // The accumulator above is scaled by 4 digits per group; shift it so
+        // its implied scale matches the `scale` the server actually sent.
+        let fractional_groups = digits.len() as i64 - integer_groups;
+        let implied_scale = 4 * fractional_groups;
+        let delta = scale as i64 - implied_scale;
+        if delta > 0 {
+            mantissa = mantissa
+                .checked_mul(10i128.pow(delta as u32))
+                .ok_or_else(|| "Numeric value out of range for rust_decimal::Decimal")?;
+        } else if delta < 0 {
+            // Guaranteed to divide evenly: Pg only ever emits whole base-10000
+            // groups, so the digits being dropped are all zero.
+            mantissa /= 10i128.pow((-delta) as u32);
+        }
+
+        // `Decimal`'s mantissa is a 96-bit unsigned integer; anything larger
+        // can't be represented without losing precision.
+        const MAX_DECIMAL_MANTISSA: i128 = (1i128 << 96) - 1;
+        if mantissa > MAX_DECIMAL_MANTISSA {
+            return Err(Box::from("Numeric value out of range for rust_decimal::Decimal"));
+        }
+
+        let mut result = Decimal::from_i128_with_scale(mantissa, scale as u32);
+        if negative {
+            result.set_sign_negative(true);
+        }
+        Ok(result)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn decimal_to_pg_numeric_retains_sign() {
+            let decimal = Decimal::new(1234, 1);
+            let expected = PgNumeric::Positive { weight: 0, scale: 1, digits: vec![123, 4000] };
+            assert_eq!(expected, PgNumeric::from(&decimal));
+
+            let decimal = Decimal::new(-1234, 1);
+            let expected = PgNumeric::Negative { weight: 0, scale: 1, digits: vec![123, 4000] };
+            assert_eq!(expected, PgNumeric::from(&decimal));
+        }
+
+        #[test]
+        fn decimal_to_pg_numeric_handles_sub_one_values() {
+            let decimal = Decimal::new(1, 1);
+            let expected = PgNumeric::Positive { weight: -1, scale: 1, digits: vec![1000] };
+            assert_eq!(expected, PgNumeric::from(&decimal));
+        }
+
+        #[test]
+        fn decimal_to_pg_numeric_round_trips_whole_numbers() {
+            // With `scale == 0` no fractional padding is added, so the
+            // `weight` must come from the integer digits alone.
+            let decimal = Decimal::new(3, 0);
+            let numeric = PgNumeric::from(&decimal);
+            assert_eq!(decimal, pg_numeric_to_decimal(numeric).unwrap());
+
+            let decimal = Decimal::new(1234, 0);
+            let numeric = PgNumeric::from(&decimal);
+            assert_eq!(decimal, pg_numeric_to_decimal(numeric).unwrap());
+        }
+
+        #[test]
+        fn decimal_to_pg_numeric_round_trips_scale_multiple_of_four() {
+            let decimal = Decimal::new(12340, 4);
+            let numeric = PgNumeric::from(&decimal);
+            assert_eq!(decimal, pg_numeric_to_decimal(numeric).unwrap());
+        }
+
+        #[test]
+        fn pg_numeric_to_decimal_handles_sub_one_values() {
+            let input = PgNumeric::Positive { weight: -1, scale: 1, digits: vec![1000] };
+            let expected = Decimal::new(1, 1);
+            assert_eq!(expected, pg_numeric_to_decimal(input).unwrap());
+        }
+
+        #[test]
+        fn pg_numeric_to_decimal_detects_overflow() {
+            // 9 groups of `9999` is a 36-digit integer, far beyond the ~28-29
+            // significant digits a `Decimal` can hold.
+            let input = PgNumeric::Positive {
+                weight: 8,
+                scale: 0,
+                digits: vec![9999; 9],
+            };
+            assert!(pg_numeric_to_decimal(input).is_err());
+        }
+
+        #[test]
+        fn pg_numeric_to_decimal_detects_scale_out_of_range() {
+            // A tiny mantissa with a scale beyond `Decimal::MAX_SCALE` (e.g.
+            // from a `NUMERIC(40, 35)` column) must error, not panic, even
+            // though the magnitude itself comfortably fits.
+            let input = PgNumeric::Positive { weight: -9, scale: 35, digits: vec![1] };
+            assert!(pg_numeric_to_decimal(input).is_err());
+        }
     }
 }